@@ -1,15 +1,104 @@
-use bytes::BufMut;
+use std::future::Future;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::pin::Pin;
+
+use bytes::{Buf, BufMut};
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
-use tokio::net::UdpSocket;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
 
 trait Serialize {
-    fn serialize(&self, dst: &mut dyn BufMut);
+    /// Writes the wire-format encoding of `self` to `dst`. Fails only for
+    /// types whose encoding can be invalid for reasons not enforced by the
+    /// Rust type system, such as an over-long [`Domain`].
+    fn serialize(&self, dst: &mut dyn BufMut) -> Result<()>;
+}
+
+trait Deserialize: Sized {
+    /// Reads the wire-format encoding of `Self` from `src`. Fails rather than
+    /// panicking on any malformed or short input, since replies are decoded
+    /// from packets sent by servers we don't control (see [`resolve`]).
+    fn parse(src: &mut dyn Buf) -> Result<Self>;
+}
+
+/// Reads a big-endian `u16` at `*cursor` within `packet`, advancing it.
+fn read_u16(packet: &[u8], cursor: &mut usize) -> Result<u16> {
+    let bytes = packet
+        .get(*cursor..*cursor + 2)
+        .ok_or_else(|| eyre!("unexpected end of packet reading a u16 at offset {cursor}"))?;
+    let value = u16::from_be_bytes(bytes.try_into().unwrap());
+    *cursor += 2;
+    Ok(value)
+}
+
+/// Reads a big-endian `u32` at `*cursor` within `packet`, advancing it.
+fn read_u32(packet: &[u8], cursor: &mut usize) -> Result<u32> {
+    let bytes = packet
+        .get(*cursor..*cursor + 4)
+        .ok_or_else(|| eyre!("unexpected end of packet reading a u32 at offset {cursor}"))?;
+    let value = u32::from_be_bytes(bytes.try_into().unwrap());
+    *cursor += 4;
+    Ok(value)
+}
+
+/// The structured contents of a [`DnsHeader`]'s flags word.
+///
+/// See <https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.1>.
+#[allow(dead_code)] // not every field is consumed by this binary
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Flags {
+    /// Set in responses, clear in queries.
+    qr: bool,
+    /// 4 bits; always `0` (a standard query) for the queries we send.
+    opcode: u8,
+    /// Set when the responding server is authoritative for the domain.
+    authoritative: bool,
+    /// Set when the reply was cut short and should be retried over TCP.
+    truncated: bool,
+    recursion_desired: bool,
+    /// Set when the responding server supports recursion.
+    recursion_available: bool,
+    /// 3 reserved bits; always `0`.
+    z: u8,
+    /// 4 bits; e.g. `0` (NOERROR), `2` (SERVFAIL) or `3` (NXDOMAIN).
+    rcode: u8,
+}
+
+impl Flags {
+    const RCODE_NOERROR: u8 = 0;
+    const RCODE_SERVFAIL: u8 = 2;
+    const RCODE_NXDOMAIN: u8 = 3;
+
+    fn to_u16(self) -> u16 {
+        (u16::from(self.qr) << 15)
+            | (u16::from(self.opcode & 0xF) << 11)
+            | (u16::from(self.authoritative) << 10)
+            | (u16::from(self.truncated) << 9)
+            | (u16::from(self.recursion_desired) << 8)
+            | (u16::from(self.recursion_available) << 7)
+            | (u16::from(self.z & 0x7) << 4)
+            | u16::from(self.rcode & 0xF)
+    }
+
+    fn from_u16(v: u16) -> Self {
+        Flags {
+            qr: v & (1 << 15) != 0,
+            opcode: ((v >> 11) & 0xF) as u8,
+            authoritative: v & (1 << 10) != 0,
+            truncated: v & (1 << 9) != 0,
+            recursion_desired: v & (1 << 8) != 0,
+            recursion_available: v & (1 << 7) != 0,
+            z: ((v >> 4) & 0x7) as u8,
+            rcode: (v & 0xF) as u8,
+        }
+    }
 }
 
 #[derive(Debug, Default)]
 struct DnsHeader {
     id: u16,
-    flags: u16,
+    flags: Flags,
     num_questions: u16,
     num_answers: u16,
     num_authorities: u16,
@@ -17,13 +106,33 @@ struct DnsHeader {
 }
 
 impl Serialize for DnsHeader {
-    fn serialize(&self, dst: &mut dyn BufMut) {
+    fn serialize(&self, dst: &mut dyn BufMut) -> Result<()> {
         dst.put_u16(self.id);
-        dst.put_u16(self.flags);
+        dst.put_u16(self.flags.to_u16());
         dst.put_u16(self.num_questions);
         dst.put_u16(self.num_answers);
         dst.put_u16(self.num_authorities);
         dst.put_u16(self.num_additionals);
+        Ok(())
+    }
+}
+
+impl Deserialize for DnsHeader {
+    fn parse(src: &mut dyn Buf) -> Result<Self> {
+        if src.remaining() < 12 {
+            return Err(eyre!(
+                "DNS header requires at least 12 bytes, got {}",
+                src.remaining()
+            ));
+        }
+        Ok(DnsHeader {
+            id: src.get_u16(),
+            flags: Flags::from_u16(src.get_u16()),
+            num_questions: src.get_u16(),
+            num_answers: src.get_u16(),
+            num_authorities: src.get_u16(),
+            num_additionals: src.get_u16(),
+        })
     }
 }
 
@@ -35,24 +144,140 @@ struct DnsQuestion<'a> {
 }
 
 impl Serialize for DnsQuestion<'_> {
-    fn serialize(&self, dst: &mut dyn BufMut) {
-        self.name.serialize(dst);
+    fn serialize(&self, dst: &mut dyn BufMut) -> Result<()> {
+        self.name.serialize(dst)?;
         dst.put_u16(self.ty as u16);
         dst.put_u16(self.class as u16);
+        Ok(())
+    }
+}
+
+impl DnsQuestion<'static> {
+    fn parse(packet: &[u8], cursor: &mut usize) -> Result<Self> {
+        Ok(DnsQuestion {
+            name: Domain::parse(packet, cursor)?,
+            ty: Type::from_u16(read_u16(packet, cursor)?)?,
+            class: Class::from_u16(read_u16(packet, cursor)?)?,
+        })
     }
 }
 
 #[derive(Debug)]
-struct Domain<'a>(&'a [u8]);
+enum Domain<'a> {
+    /// A dotted-label domain name provided by the caller, serialized as-is.
+    Input(&'a [u8]),
+    /// A domain name decoded from a wire-format packet.
+    Parsed(String),
+}
 
 impl Serialize for Domain<'_> {
-    fn serialize(&self, dst: &mut dyn BufMut) {
-        for part in self.0.split(|c| c == &b'.') {
-            let len = part.len().try_into().unwrap();
-            dst.put_u8(len);
+    fn serialize(&self, dst: &mut dyn BufMut) -> Result<()> {
+        let mut parts: Vec<&[u8]> = self.as_bytes().split(|c| c == &b'.').collect();
+        // A trailing dot (a fully-qualified name) encodes the same root
+        // label that we always terminate with below, so drop it rather
+        // than reject it as an empty label.
+        if parts.last().is_some_and(|part| part.is_empty()) {
+            parts.pop();
+        }
+
+        // +1 for each label's length byte, +1 for the terminating root label.
+        let encoded_len: usize = parts.iter().map(|part| part.len() + 1).sum::<usize>() + 1;
+        if encoded_len > 255 {
+            return Err(eyre!(
+                "domain name is {encoded_len} bytes encoded, exceeding the 255-byte limit"
+            ));
+        }
+
+        for part in parts {
+            if part.is_empty() || part.len() > 63 {
+                return Err(eyre!(
+                    "domain label must be 1..=63 bytes, got {} bytes",
+                    part.len()
+                ));
+            }
+            dst.put_u8(part.len() as u8);
             dst.put_slice(part);
         }
         dst.put_u8(0);
+        Ok(())
+    }
+}
+
+impl Domain<'_> {
+    /// The dotted-label bytes of this name, regardless of whether it was
+    /// provided by the caller or decoded from a packet.
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Domain::Input(name) => name,
+            Domain::Parsed(name) => name.as_bytes(),
+        }
+    }
+}
+
+impl Domain<'static> {
+    /// The bits that mark a label length byte as a compression pointer
+    /// instead of a literal label length. See
+    /// <https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.4>.
+    const POINTER_TAG: u8 = 0b1100_0000;
+
+    /// Reads a (possibly compressed) domain name starting at `*cursor`
+    /// within `packet`, advancing `*cursor` past the name (or, if the name
+    /// ends in a pointer, past just the two pointer bytes).
+    fn parse(packet: &[u8], cursor: &mut usize) -> Result<Self> {
+        let mut parts = vec![];
+        let mut pos = *cursor;
+        let mut jumped = false;
+        // Start of the run of labels currently being read: the name itself,
+        // or the target of the last pointer followed.
+        let mut segment_start = *cursor;
+
+        loop {
+            let len = *packet.get(pos).ok_or_else(|| {
+                eyre!("unexpected end of packet reading a domain label at offset {pos}")
+            })?;
+            if len & Self::POINTER_TAG == Self::POINTER_TAG {
+                let pointer_pos = pos;
+                let next = *packet.get(pos + 1).ok_or_else(|| {
+                    eyre!("truncated domain name compression pointer at offset {pos}")
+                })?;
+                let offset = (u16::from(len & !Self::POINTER_TAG) << 8) | u16::from(next);
+                if !jumped {
+                    *cursor = pointer_pos + 2;
+                    jumped = true;
+                }
+                // A pointer must target bytes strictly before the labels
+                // being read, so each jump lands earlier than the last and
+                // following pointers always terminates, even on malicious
+                // packets that try to build a cycle.
+                if offset as usize >= segment_start {
+                    return Err(eyre!(
+                        "domain name compression pointer at offset {pointer_pos} does not point backwards"
+                    ));
+                }
+                pos = offset as usize;
+                segment_start = pos;
+                continue;
+            }
+
+            pos += 1;
+            if len == 0 {
+                break;
+            }
+            let end = pos + len as usize;
+            let label = packet.get(pos..end).ok_or_else(|| {
+                eyre!("domain label at offset {pos} extends past the end of the packet")
+            })?;
+            // Labels are arbitrary octets per RFC 1035, not guaranteed
+            // text, so decode permissively rather than rejecting the name.
+            parts.push(String::from_utf8_lossy(label).into_owned());
+            pos = end;
+        }
+
+        if !jumped {
+            *cursor = pos;
+        }
+
+        Ok(Domain::Parsed(parts.join(".")))
     }
 }
 
@@ -61,6 +286,27 @@ impl Serialize for Domain<'_> {
 #[derive(Copy, Clone, Debug)]
 enum Type {
     A = 0x1,
+    Ns = 0x2,
+    Cname = 0x5,
+    Soa = 0x6,
+    Mx = 0xf,
+    Txt = 0x10,
+    Aaaa = 0x1c,
+}
+
+impl Type {
+    fn from_u16(v: u16) -> Result<Self> {
+        Ok(match v {
+            0x1 => Type::A,
+            0x2 => Type::Ns,
+            0x5 => Type::Cname,
+            0x6 => Type::Soa,
+            0xf => Type::Mx,
+            0x10 => Type::Txt,
+            0x1c => Type::Aaaa,
+            _ => return Err(eyre!("unsupported record type: {v}")),
+        })
+    }
 }
 
 /// See <https://datatracker.ietf.org/doc/html/rfc1035#section-3.2.4>.
@@ -71,24 +317,209 @@ enum Class {
     In = 0x1,
 }
 
+impl Class {
+    fn from_u16(v: u16) -> Result<Self> {
+        Ok(match v {
+            0x1 => Class::In,
+            _ => return Err(eyre!("unsupported class: {v}")),
+        })
+    }
+}
+
+/// The decoded contents of a resource record, dispatched on its [`Type`].
+///
+/// See <https://datatracker.ietf.org/doc/html/rfc1035#section-3.3>.
+#[allow(dead_code)] // not every variant/field is consumed by this binary
+#[derive(Debug)]
+enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ns(Domain<'static>),
+    Cname(Domain<'static>),
+    Mx {
+        preference: u16,
+        exchange: Domain<'static>,
+    },
+    Txt(Vec<u8>),
+    Soa {
+        mname: Domain<'static>,
+        rname: Domain<'static>,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+}
+
+impl RecordData {
+    /// Decodes the RDATA of a resource record of type `ty` starting at
+    /// `*cursor`, advancing `*cursor` past the `rdlength` bytes it occupies
+    /// (even for variants whose own parsing, e.g. a compressed [`Domain`],
+    /// would otherwise stop short).
+    fn parse(ty: Type, packet: &[u8], cursor: &mut usize, rdlength: usize) -> Result<Self> {
+        let rdata_end = *cursor + rdlength;
+        let rdata = packet
+            .get(*cursor..rdata_end)
+            .ok_or_else(|| eyre!("RDATA at offset {cursor} extends past the end of the packet"))?;
+        let data = match ty {
+            Type::A => {
+                let bytes: [u8; 4] = rdata
+                    .try_into()
+                    .map_err(|_| eyre!("A record RDATA must be 4 bytes, got {}", rdata.len()))?;
+                RecordData::A(Ipv4Addr::from(bytes))
+            }
+            Type::Aaaa => {
+                let bytes: [u8; 16] = rdata.try_into().map_err(|_| {
+                    eyre!("AAAA record RDATA must be 16 bytes, got {}", rdata.len())
+                })?;
+                RecordData::Aaaa(Ipv6Addr::from(bytes))
+            }
+            Type::Ns => RecordData::Ns(Domain::parse(packet, cursor)?),
+            Type::Cname => RecordData::Cname(Domain::parse(packet, cursor)?),
+            Type::Mx => {
+                let preference = read_u16(packet, cursor)?;
+                let exchange = Domain::parse(packet, cursor)?;
+                RecordData::Mx {
+                    preference,
+                    exchange,
+                }
+            }
+            Type::Txt => RecordData::Txt(rdata.to_vec()),
+            Type::Soa => {
+                let mname = Domain::parse(packet, cursor)?;
+                let rname = Domain::parse(packet, cursor)?;
+                let serial = read_u32(packet, cursor)?;
+                let refresh = read_u32(packet, cursor)?;
+                let retry = read_u32(packet, cursor)?;
+                let expire = read_u32(packet, cursor)?;
+                let minimum = read_u32(packet, cursor)?;
+                RecordData::Soa {
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                }
+            }
+        };
+        *cursor = rdata_end;
+        Ok(data)
+    }
+}
+
+/// A single resource record, as found in the answer, authority and
+/// additional sections of a [`DnsPacket`].
+///
+/// See <https://datatracker.ietf.org/doc/html/rfc1035#section-3.2.1>.
+#[allow(dead_code)] // not every field is consumed by this binary
+#[derive(Debug)]
+struct DnsAnswer {
+    name: Domain<'static>,
+    ty: Type,
+    class: Class,
+    ttl: u32,
+    rdata: RecordData,
+}
+
+impl DnsAnswer {
+    fn parse(packet: &[u8], cursor: &mut usize) -> Result<Self> {
+        let name = Domain::parse(packet, cursor)?;
+        let ty = Type::from_u16(read_u16(packet, cursor)?)?;
+        let class = Class::from_u16(read_u16(packet, cursor)?)?;
+        let ttl = read_u32(packet, cursor)?;
+        let rdlength = read_u16(packet, cursor)? as usize;
+        let rdata = RecordData::parse(ty, packet, cursor, rdlength)?;
+        Ok(DnsAnswer {
+            name,
+            ty,
+            class,
+            ttl,
+            rdata,
+        })
+    }
+}
+
+/// A fully decoded DNS message: header, questions and the three resource
+/// record sections.
+#[allow(dead_code)] // not every field is consumed by this binary
+#[derive(Debug)]
+struct DnsPacket {
+    header: DnsHeader,
+    questions: Vec<DnsQuestion<'static>>,
+    answers: Vec<DnsAnswer>,
+    authorities: Vec<DnsAnswer>,
+    additionals: Vec<DnsAnswer>,
+}
+
+impl DnsPacket {
+    fn parse(packet: &[u8]) -> Result<Self> {
+        let mut header_src = packet;
+        let header = DnsHeader::parse(&mut header_src)?;
+        let mut cursor = packet.len() - header_src.len();
+
+        let questions = (0..header.num_questions)
+            .map(|_| DnsQuestion::parse(packet, &mut cursor))
+            .collect::<Result<Vec<_>>>()?;
+        let answers = (0..header.num_answers)
+            .map(|_| DnsAnswer::parse(packet, &mut cursor))
+            .collect::<Result<Vec<_>>>()?;
+        let authorities = (0..header.num_authorities)
+            .map(|_| DnsAnswer::parse(packet, &mut cursor))
+            .collect::<Result<Vec<_>>>()?;
+        let additionals = (0..header.num_additionals)
+            .map(|_| DnsAnswer::parse(packet, &mut cursor))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(DnsPacket {
+            header,
+            questions,
+            answers,
+            authorities,
+            additionals,
+        })
+    }
+}
+
 struct DnsQuery<'a> {
     header: DnsHeader,
     question: DnsQuestion<'a>,
 }
 
 impl<'a> DnsQuery<'a> {
+    #[allow(dead_code)] // kept for callers that want an upstream resolver to recurse for them
     pub fn new(id: u16, domain: &'a [u8], ty: Type) -> DnsQuery<'a> {
-        const RECURSION_DESIRED: u16 = 1 << 8;
+        DnsQuery {
+            header: DnsHeader {
+                id,
+                flags: Flags {
+                    recursion_desired: true,
+                    ..Default::default()
+                },
+                num_questions: 1,
+                ..Default::default()
+            },
+            question: DnsQuestion {
+                name: Domain::Input(domain),
+                ty,
+                class: Class::In,
+            },
+        }
+    }
 
+    /// Builds a query with the recursion-desired flag cleared, for servers
+    /// we expect to answer authoritatively or refer us elsewhere, rather
+    /// than perform the recursion on our behalf.
+    pub fn new_iterative(id: u16, domain: &'a [u8], ty: Type) -> DnsQuery<'a> {
         DnsQuery {
             header: DnsHeader {
                 id,
-                flags: RECURSION_DESIRED,
                 num_questions: 1,
                 ..Default::default()
             },
             question: DnsQuestion {
-                name: Domain(domain),
+                name: Domain::Input(domain),
                 ty,
                 class: Class::In,
             },
@@ -97,9 +528,10 @@ impl<'a> DnsQuery<'a> {
 }
 
 impl Serialize for DnsQuery<'_> {
-    fn serialize(&self, dst: &mut dyn BufMut) {
-        self.header.serialize(dst);
-        self.question.serialize(dst);
+    fn serialize(&self, dst: &mut dyn BufMut) -> Result<()> {
+        self.header.serialize(dst)?;
+        self.question.serialize(dst)?;
+        Ok(())
     }
 }
 
@@ -110,26 +542,161 @@ async fn main() -> Result<()> {
 }
 
 async fn run() -> Result<()> {
+    let ip = resolve(b"example.com").await?;
+    println!("example.com resolves to {ip}");
+    Ok(())
+}
+
+/// Sends `query` to `server` over UDP and parses its reply, transparently
+/// retrying over TCP if the UDP reply was truncated.
+async fn send_query(server: Ipv4Addr, query: &DnsQuery<'_>) -> Result<DnsPacket> {
     let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
-    socket.connect(("8.8.8.8", 53)).await?;
+    socket.connect((server, 53)).await?;
 
-    let query = DnsQuery::new(fastrand::u16(..), b"example.com", Type::A);
+    let mut buf = vec![];
+    query.serialize(&mut buf)?;
+    socket.send(&buf).await?;
 
-    {
-        let mut buf = vec![];
-        query.serialize(&mut buf);
-        socket.send(&buf).await?;
-    }
+    let mut buf = vec![0; 1024];
+    let n = socket.recv(&mut buf).await?;
+    let packet = DnsPacket::parse(&buf[..n])?;
+    check_reply_id(query, &packet)?;
 
-    {
-        let mut buf = vec![0; 1024];
-        let n = socket.recv(&mut buf).await?;
-        println!("got {n} bytes");
+    if packet.header.flags.truncated {
+        return send_query_tcp(server, query).await;
     }
+    Ok(packet)
+}
 
+/// Rejects a reply whose transaction id doesn't match the query that was
+/// sent. This is half of the standard defense against off-path response
+/// spoofing (the other half, a random source port, is provided by binding
+/// to port 0), and matters because `server` is not under our control.
+fn check_reply_id(query: &DnsQuery<'_>, reply: &DnsPacket) -> Result<()> {
+    if reply.header.id != query.header.id {
+        return Err(eyre!(
+            "reply transaction id {:#06x} does not match query id {:#06x}",
+            reply.header.id,
+            query.header.id
+        ));
+    }
     Ok(())
 }
 
+/// Sends `query` to `server` over TCP, using the two-byte big-endian length
+/// prefix framing required by <https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.2>.
+async fn send_query_tcp(server: Ipv4Addr, query: &DnsQuery<'_>) -> Result<DnsPacket> {
+    let mut stream = TcpStream::connect((server, 53)).await?;
+
+    let mut buf = vec![];
+    query.serialize(&mut buf)?;
+    stream.write_u16(buf.len().try_into()?).await?;
+    stream.write_all(&buf).await?;
+
+    let len = stream.read_u16().await? as usize;
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf).await?;
+    let packet = DnsPacket::parse(&buf)?;
+    check_reply_id(query, &packet)?;
+    Ok(packet)
+}
+
+/// One of the IANA root nameservers (a.root-servers.net), used as the
+/// starting point for the iterative walk in [`resolve`].
+const ROOT_SERVER: Ipv4Addr = Ipv4Addr::new(198, 41, 0, 4);
+
+/// Resolves `domain` to an IPv4 address by performing the recursive walk
+/// ourselves: starting at a root server, we follow NS referrals (using glue
+/// A records when present, or resolving the nameserver's own name otherwise)
+/// until some server answers the question directly.
+///
+/// Only `A` lookups are supported, since the return type is an [`Ipv4Addr`].
+fn resolve(domain: &[u8]) -> Pin<Box<dyn Future<Output = Result<Ipv4Addr>> + '_>> {
+    resolve_with_depth(domain, 0)
+}
+
+/// Upper bound on the number of NS referrals (including glueless
+/// nameserver-name resolutions) followed by [`resolve`]. Servers we don't
+/// control can refer us in a cycle (e.g. `a.com`'s NS is `ns.b.com` and
+/// `b.com`'s NS is `ns.a.com`, neither glued), which would otherwise recurse
+/// forever and blow the stack.
+const MAX_REFERRAL_DEPTH: u32 = 16;
+
+fn resolve_with_depth(
+    domain: &[u8],
+    depth: u32,
+) -> Pin<Box<dyn Future<Output = Result<Ipv4Addr>> + '_>> {
+    Box::pin(async move {
+        let mut server = ROOT_SERVER;
+        let mut referrals = depth;
+
+        loop {
+            if referrals >= MAX_REFERRAL_DEPTH {
+                return Err(eyre!(
+                    "exceeded max referral depth ({MAX_REFERRAL_DEPTH}) resolving {:?}",
+                    String::from_utf8_lossy(domain)
+                ));
+            }
+
+            let query = DnsQuery::new_iterative(fastrand::u16(..), domain, Type::A);
+            let packet = send_query(server, &query).await?;
+
+            match packet.header.flags.rcode {
+                Flags::RCODE_NOERROR => {}
+                Flags::RCODE_NXDOMAIN => {
+                    return Err(eyre!(
+                        "{:?} does not exist (NXDOMAIN)",
+                        String::from_utf8_lossy(domain)
+                    ))
+                }
+                Flags::RCODE_SERVFAIL => {
+                    return Err(eyre!(
+                        "server failed (SERVFAIL) resolving {:?}",
+                        String::from_utf8_lossy(domain)
+                    ))
+                }
+                rcode => {
+                    return Err(eyre!(
+                        "server returned rcode {rcode} resolving {:?}",
+                        String::from_utf8_lossy(domain)
+                    ))
+                }
+            }
+
+            for answer in &packet.answers {
+                if let RecordData::A(ip) = answer.rdata {
+                    return Ok(ip);
+                }
+            }
+
+            let Some(ns_name) = packet.authorities.iter().find_map(|a| match &a.rdata {
+                RecordData::Ns(name) => Some(name),
+                _ => None,
+            }) else {
+                return Err(eyre!(
+                    "no answer and no NS referral resolving {:?}",
+                    String::from_utf8_lossy(domain)
+                ));
+            };
+
+            let glue = packet.additionals.iter().find_map(|a| match &a.rdata {
+                // Names are case-insensitive per RFC 1035 §2.3.3, and servers
+                // are free to echo the owner name back in different casing.
+                RecordData::A(ip) if a.name.as_bytes().eq_ignore_ascii_case(ns_name.as_bytes()) => {
+                    Some(*ip)
+                }
+                _ => None,
+            });
+
+            referrals += 1;
+            server = match glue {
+                Some(ip) => ip,
+                None => resolve_with_depth(ns_name.as_bytes(), referrals).await?,
+            };
+        }
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -139,7 +706,7 @@ mod tests {
             #[test]
             fn $name() {
                 let mut bytes = vec![];
-                $serialize.serialize(&mut bytes);
+                $serialize.serialize(&mut bytes).unwrap();
                 assert_eq!(bytes, $expected);
             }
         };
@@ -149,7 +716,7 @@ mod tests {
         test_dns_header,
         DnsHeader {
             id: 0x1314,
-            flags: 1,
+            flags: Flags::from_u16(1),
             num_questions: 2,
             num_answers: 3,
             num_authorities: 4,
@@ -161,7 +728,7 @@ mod tests {
     test_serialize!(
         test_dns_question,
         DnsQuestion {
-            name: Domain(b"foo"),
+            name: Domain::Input(b"foo"),
             ty: Type::A,
             class: Class::In,
         },
@@ -170,23 +737,62 @@ mod tests {
 
     test_serialize!(
         test_domain,
-        Domain(b"google.com.br"),
+        Domain::Input(b"google.com.br"),
         b"\x06google\x03com\x02br\x00",
     );
 
+    test_serialize!(
+        test_domain_trailing_dot,
+        Domain::Input(b"google.com."),
+        b"\x06google\x03com\x00",
+    );
+
+    #[test]
+    fn test_domain_serialize_rejects_empty_label() {
+        let mut bytes = vec![];
+        let err = Domain::Input(b"foo..com")
+            .serialize(&mut bytes)
+            .unwrap_err();
+        assert!(err.to_string().contains("1..=63 bytes"));
+    }
+
+    #[test]
+    fn test_domain_serialize_rejects_long_label() {
+        let label = vec![b'a'; 64];
+        let mut bytes = vec![];
+        let err = Domain::Input(&label).serialize(&mut bytes).unwrap_err();
+        assert!(err.to_string().contains("1..=63 bytes"));
+    }
+
+    #[test]
+    fn test_domain_serialize_rejects_long_name() {
+        // 4 labels of 63 bytes, joined by dots, encode to 257 bytes: two
+        // over the 255-byte limit.
+        let label = "a".repeat(63);
+        let name = [label.as_str(); 4].join(".");
+        let mut bytes = vec![];
+        let err = Domain::Input(name.as_bytes())
+            .serialize(&mut bytes)
+            .unwrap_err();
+        assert!(err.to_string().contains("255-byte limit"));
+    }
+
     test_serialize!(
         test_dns_query,
         DnsQuery {
             header: DnsHeader {
                 id: 0xABCD,
-                flags: 1 << 8,
+                flags: Flags {
+                    recursion_desired: true,
+                    ..Default::default()
+                },
                 num_questions: 1,
                 num_answers: 0,
                 num_authorities: 0,
                 num_additionals: 0
             },
             question: DnsQuestion {
-                name: Domain(b"example.com"),
+                name: Domain::Input(b"example.com"),
                 ty: Type::A,
                 class: Class::In
             }
@@ -200,4 +806,133 @@ mod tests {
         // Same as above.
         b"\xAB\xCD\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07example\x03com\x00\x00\x01\x00\x01",
     );
+
+    macro_rules! test_parse {
+        ($name:ident, $ty:ty, $bytes:expr, $expected:expr $(,)?) => {
+            #[test]
+            fn $name() {
+                let mut bytes: &[u8] = $bytes;
+                let parsed = <$ty>::parse(&mut bytes).unwrap();
+                assert_eq!(format!("{parsed:?}"), format!("{:?}", $expected));
+            }
+        };
+    }
+
+    test_parse!(
+        test_dns_header_parse,
+        DnsHeader,
+        b"\x13\x14\x00\x01\x00\x02\x00\x03\x00\x04\x00\x05",
+        DnsHeader {
+            id: 0x1314,
+            flags: Flags::from_u16(1),
+            num_questions: 2,
+            num_answers: 3,
+            num_authorities: 4,
+            num_additionals: 5,
+        },
+    );
+
+    #[test]
+    fn test_flags_round_trip() {
+        // QR, AA, RD and RA set, opcode/z zero, rcode NXDOMAIN.
+        let flags = Flags {
+            qr: true,
+            opcode: 0,
+            authoritative: true,
+            truncated: false,
+            recursion_desired: true,
+            recursion_available: true,
+            z: 0,
+            rcode: Flags::RCODE_NXDOMAIN,
+        };
+        assert_eq!(flags.to_u16(), 0x8583);
+        assert_eq!(Flags::from_u16(0x8583), flags);
+    }
+
+    #[test]
+    fn test_domain_parse() {
+        let packet: &[u8] = b"\x06google\x03com\x00";
+        let mut cursor = 0;
+        let domain = Domain::parse(packet, &mut cursor).unwrap();
+        assert_eq!(format!("{domain:?}"), r#"Parsed("google.com")"#);
+        assert_eq!(cursor, packet.len());
+    }
+
+    #[test]
+    fn test_domain_parse_with_compression_pointer() {
+        // "example.com" spelled out at offset 0, followed by a pointer back
+        // to it at offset 13.
+        let packet: &[u8] = b"\x07example\x03com\x00\xC0\x00";
+        let mut cursor = 13;
+        let domain = Domain::parse(packet, &mut cursor).unwrap();
+        assert_eq!(format!("{domain:?}"), r#"Parsed("example.com")"#);
+        // Only the two pointer bytes were consumed.
+        assert_eq!(cursor, 15);
+    }
+
+    #[test]
+    fn test_domain_parse_rejects_forward_pointer() {
+        let packet: &[u8] = b"\xC0\x02\x00";
+        let mut cursor = 0;
+        let err = Domain::parse(packet, &mut cursor).unwrap_err();
+        assert!(err.to_string().contains("does not point backwards"));
+    }
+
+    #[test]
+    fn test_dns_packet_parse() {
+        // A single-question, single-answer reply for "example.com" resolving
+        // to 93.184.216.34, with no authority or additional records.
+        let bytes: &[u8] = b"\xAB\xCD\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\
+            \x07example\x03com\x00\x00\x01\x00\x01\
+            \x07example\x03com\x00\x00\x01\x00\x01\x00\x00\x01\x2C\x00\x04\x5D\xB8\xD8\x22";
+        let packet = DnsPacket::parse(bytes).unwrap();
+
+        assert_eq!(packet.header.num_answers, 1);
+        assert_eq!(packet.answers.len(), 1);
+        assert!(
+            matches!(packet.answers[0].rdata, RecordData::A(ip) if ip == Ipv4Addr::new(93, 184, 216, 34))
+        );
+    }
+
+    #[test]
+    fn test_dns_packet_parse_with_compressed_answer_name() {
+        // Same as above, but the answer's name is a compression pointer back
+        // to the question's name instead of being spelled out again.
+        let bytes: &[u8] = b"\xAB\xCD\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\
+            \x07example\x03com\x00\x00\x01\x00\x01\
+            \xC0\x0C\x00\x01\x00\x01\x00\x00\x01\x2C\x00\x04\x5D\xB8\xD8\x22";
+        let packet = DnsPacket::parse(bytes).unwrap();
+
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(
+            format!("{:?}", packet.answers[0].name),
+            r#"Parsed("example.com")"#
+        );
+        assert!(
+            matches!(packet.answers[0].rdata, RecordData::A(ip) if ip == Ipv4Addr::new(93, 184, 216, 34))
+        );
+    }
+
+    #[test]
+    fn test_dns_packet_parse_mx_record_with_mid_name_compression() {
+        // An MX record for "example.com" whose exchange, "mail.example.com",
+        // reuses "example.com" from the question via a pointer that follows
+        // its own literal "mail" label.
+        let bytes: &[u8] = b"\xAB\xCD\x81\x80\x00\x01\x00\x01\x00\x00\x00\x00\
+            \x07example\x03com\x00\x00\x0f\x00\x01\
+            \xC0\x0C\x00\x0f\x00\x01\x00\x00\x01\x2C\x00\x09\x00\x0A\x04mail\xC0\x0C";
+        let packet = DnsPacket::parse(bytes).unwrap();
+
+        assert_eq!(packet.answers.len(), 1);
+        match &packet.answers[0].rdata {
+            RecordData::Mx {
+                preference,
+                exchange,
+            } => {
+                assert_eq!(*preference, 10);
+                assert_eq!(format!("{exchange:?}"), r#"Parsed("mail.example.com")"#);
+            }
+            other => panic!("expected an MX record, got {other:?}"),
+        }
+    }
 }